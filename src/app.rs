@@ -6,11 +6,16 @@ use crate::{
     topology::{self, RunningTopology},
     trace, unit_test, validate,
 };
-use std::{cmp::max, collections::HashMap, path::PathBuf};
+use std::{cmp::max, collections::HashMap, path::PathBuf, time::Duration};
 
 use futures::StreamExt;
 use tokio::sync::mpsc;
 
+#[cfg(feature = "allocation-tracing")]
+mod heap_profiling;
+mod state_machine;
+mod task_supervisor;
+
 #[cfg(feature = "sources-host_metrics")]
 use crate::sources::host_metrics;
 #[cfg(feature = "api-client")]
@@ -20,22 +25,32 @@ use crate::top;
 use crate::service;
 
 use crate::internal_events::{
-    VectorConfigLoadFailed, VectorQuit, VectorRecoveryFailed, VectorReloadFailed, VectorReloaded,
-    VectorStarted, VectorStopped,
+    VectorConfigLoadFailed, VectorGracefulShutdownTimeout, VectorQuit, VectorRecoveryFailed,
+    VectorReloadFailed, VectorReloaded, VectorStarted, VectorStopped,
 };
 use tokio::runtime::{self, Runtime};
 
+/// The default amount of time we wait for the topology to finish
+/// shutting down gracefully before we force-abort any remaining tasks.
+///
+/// This mirrors Kubernetes' own default `terminationGracePeriodSeconds`,
+/// so that `vector` behaves sanely even when operators don't override it.
+const DEFAULT_GRACEFUL_SHUTDOWN_LIMIT_SECS: u64 = 30;
+
 pub struct ApplicationConfig {
     pub config_paths: Vec<(PathBuf, config::FormatHint)>,
     pub topology: RunningTopology,
     pub graceful_crash: mpsc::UnboundedReceiver<()>,
     pub api: config::api::Options,
+    pub task_supervisor: task_supervisor::TaskSupervisor,
 }
 
 pub struct Application {
     opts: RootOpts,
     pub config: ApplicationConfig,
     pub runtime: Runtime,
+    #[cfg(feature = "allocation-tracing")]
+    heap_profiler: Option<heap_profiling::HeapProfiler>,
 }
 
 impl Application {
@@ -61,6 +76,9 @@ impl Application {
 
         let root_opts = opts.root;
 
+        #[cfg(feature = "allocation-tracing")]
+        let heap_profiler = root_opts.profile_heap.clone().map(heap_profiling::HeapProfiler::start);
+
         let sub_command = opts.sub_command;
 
         let color = match root_opts.color {
@@ -103,6 +121,8 @@ impl Application {
             let watch_config = root_opts.watch_config;
             let require_healthy = root_opts.require_healthy;
 
+            let mut task_supervisor = task_supervisor::TaskSupervisor::new();
+
             rt.block_on(async move {
                 if let Some(s) = sub_command {
                     let code = match s {
@@ -130,11 +150,20 @@ impl Application {
 
                 if watch_config {
                     // Start listening for config changes immediately.
-                    config::watcher::spawn_thread(config_paths.iter().map(|(path, _)| path), None)
-                        .map_err(|error| {
-                            error!(message = "Unable to start config watcher.", %error);
-                            exitcode::CONFIG
-                        })?;
+                    let (watcher_cancel_tx, watcher_cancel_rx) = mpsc::channel(1);
+                    let watcher_thread = config::watcher::spawn_thread(
+                        config_paths.iter().map(|(path, _)| path),
+                        Some(watcher_cancel_rx),
+                    )
+                    .map_err(|error| {
+                        error!(message = "Unable to start config watcher.", %error);
+                        exitcode::CONFIG
+                    })?;
+                    task_supervisor.register_thread(
+                        "config_watcher",
+                        watcher_thread,
+                        watcher_cancel_tx,
+                    );
                 }
 
                 info!(
@@ -174,6 +203,7 @@ impl Application {
                     topology,
                     graceful_crash,
                     api,
+                    task_supervisor,
                 })
             })
         }?;
@@ -182,6 +212,8 @@ impl Application {
             opts: root_opts,
             config,
             runtime: rt,
+            #[cfg(feature = "allocation-tracing")]
+            heap_profiler,
         })
     }
 
@@ -189,12 +221,20 @@ impl Application {
         let mut rt = self.runtime;
 
         let mut graceful_crash = self.config.graceful_crash;
-        let mut topology = self.config.topology;
+        let topology = self.config.topology;
+        let mut task_supervisor = self.config.task_supervisor;
+        #[cfg(feature = "allocation-tracing")]
+        let heap_profiler = self.heap_profiler;
 
         let mut config_paths = self.config.config_paths;
 
         let opts = self.opts;
 
+        let graceful_shutdown_limit = Duration::from_secs(
+            opts.graceful_shutdown_limit_secs
+                .unwrap_or(DEFAULT_GRACEFUL_SHUTDOWN_LIMIT_SECS),
+        );
+
         // Underscored to prevent warning of non-use when the `api` feature is disabled
         let _api_config = self.config.api;
 
@@ -204,7 +244,21 @@ impl Application {
 
         rt.block_on(async move {
             emit!(VectorStarted);
-            tokio::spawn(heartbeat::heartbeat());
+            task_supervisor.spawn("heartbeat", |shutdown| async move {
+                tokio::select! {
+                    _ = heartbeat::heartbeat() => {},
+                    _ = shutdown => {},
+                }
+            });
+
+            let (mut state_machine, status_rx) = state_machine::StateMachine::new();
+            state_machine.start(topology);
+
+            // Assigned alongside `api_server` to prevent the HTTP/3 listener
+            // terminating when falling out of scope; only ever `Some` when
+            // `api.protocol` opts into it.
+            #[cfg(feature = "http3-preview")]
+            let mut api_h3_server = None;
 
             #[cfg(feature = "api")]
             // Assigned to prevent the API terminating when falling out of scope.
@@ -212,22 +266,64 @@ impl Application {
                 use crate::{api, internal_events::ApiStarted};
                 use ::std::sync::Arc;
 
-                let tap_controller = topology.tap().get_controller().expect("Expected tap controller to be initialized.");
+                let tap_controller = state_machine
+                    .topology_mut()
+                    .expect("topology is running")
+                    .tap()
+                    .get_controller()
+                    .expect("Expected tap controller to be initialized.");
 
                 emit!(ApiStarted {
                     addr: _api_config.address.unwrap(),
                     playground: _api_config.playground
                 });
 
-                Some(api::Server::start(topology.config(), Arc::clone(&tap_controller)))
+                let config = state_machine.config().expect("topology is running");
+                let (shutdown_tx, shutdown_rx) = tokio::sync::oneshot::channel();
+                let (server, handle) = api::Server::start(
+                    config,
+                    Arc::clone(&tap_controller),
+                    status_rx.clone(),
+                    shutdown_rx,
+                );
+                task_supervisor.register("api", handle, shutdown_tx);
+
+                // `api.protocol` opts a config into additionally serving the
+                // same GraphQL/tap endpoint over QUIC, so `vector top` and tap
+                // consumers on lossy/high-latency links avoid head-of-line
+                // blocking on the metrics subscription.
+                #[cfg(feature = "http3-preview")]
+                if matches!(
+                    _api_config.protocol,
+                    config::api::Protocol::Http3 | config::api::Protocol::Both
+                ) {
+                    let (h3_shutdown_tx, h3_shutdown_rx) = tokio::sync::oneshot::channel();
+                    let (h3_server, h3_handle) = api::Server::start_h3(
+                        config,
+                        Arc::clone(&tap_controller),
+                        status_rx.clone(),
+                        h3_shutdown_rx,
+                    );
+                    task_supervisor.register("api-http3", h3_handle, h3_shutdown_tx);
+                    api_h3_server = Some(h3_server);
+                }
+
+                Some(server)
             } else {
                 info!(message="API is disabled, enable by setting `api.enabled` to `true` and use commands like `vector top`.");
                 None
             };
+            // Only consumed by the API server above; avoid an unused-variable
+            // warning when the `api` feature is disabled.
+            #[cfg(not(feature = "api"))]
+            drop(status_rx);
 
             let signals = signal::signals();
             tokio::pin!(signals);
-            let mut sources_finished = topology.sources_finished();
+            let mut sources_finished = state_machine
+                .topology_mut()
+                .expect("topology is running")
+                .sources_finished();
 
             let signal = loop {
                 tokio::select! {
@@ -238,62 +334,108 @@ impl Application {
                         // Reload config
                         let new_config = config::load_from_paths(&config_paths, false).map_err(handle_config_errors).ok();
 
-                        if let Some(mut new_config) = new_config {
+                        let event = if let Some(mut new_config) = new_config {
                             new_config.healthchecks.set_require_healthy(opts.require_healthy);
-                            match topology
-                                .reload_config_and_respawn(new_config)
-                                .await
-                            {
-                                Ok(true) => {
-                                    #[cfg(feature="api")]
+                            state_machine::Event::UpdateConfig(Box::new(new_config))
+                        } else {
+                            state_machine::Event::ConfigLoadFailed
+                        };
+
+                        match state_machine.handle(event).await {
+                            state_machine::Outcome::Reloaded => {
+                                #[cfg(feature = "api")]
+                                {
+                                    let config = state_machine.config().expect("topology is running");
                                     if let Some(ref api_server) = api_server {
-                                        api_server.update_config(topology.config())
+                                        api_server.update_config(config);
+                                    }
+                                    #[cfg(feature = "http3-preview")]
+                                    if let Some(ref api_h3_server) = api_h3_server {
+                                        api_h3_server.update_config(config);
                                     }
-
-                                    emit!(VectorReloaded { config_paths: &config_paths })
-                                },
-                                Ok(false) => emit!(VectorReloadFailed),
-                                // Trigger graceful shutdown for what remains of the topology
-                                Err(()) => {
-                                    emit!(VectorReloadFailed);
-                                    emit!(VectorRecoveryFailed);
-                                    break SignalTo::Shutdown;
                                 }
+
+                                emit!(VectorReloaded { config_paths: &config_paths })
+                            },
+                            state_machine::Outcome::ConfigLoadFailed => emit!(VectorConfigLoadFailed),
+                            state_machine::Outcome::ReloadFailed => emit!(VectorReloadFailed),
+                            // Trigger graceful shutdown for what remains of the topology
+                            state_machine::Outcome::Fatal => {
+                                emit!(VectorReloadFailed);
+                                emit!(VectorRecoveryFailed);
+                                break SignalTo::Shutdown;
+                            }
+                            state_machine::Outcome::Shutdown | state_machine::Outcome::Quit => {
+                                unreachable!("reload events never produce a shutdown/quit outcome")
                             }
+                        }
+
+                        if let Some(topology) = state_machine.topology_mut() {
                             sources_finished = topology.sources_finished();
-                        } else {
-                            emit!(VectorConfigLoadFailed);
                         }
                     } else {
                         break signal;
                     }
                 }
                 // Trigger graceful shutdown if a component crashed, or all sources have ended.
-                _ = graceful_crash.next() => break SignalTo::Shutdown,
-                _ = &mut sources_finished => break SignalTo::Shutdown,
+                _ = graceful_crash.next() => {
+                    state_machine.handle(state_machine::Event::ComponentCrashed).await;
+                    break SignalTo::Shutdown;
+                }
+                _ = &mut sources_finished => {
+                    state_machine.handle(state_machine::Event::SourcesFinished).await;
+                    break SignalTo::Shutdown;
+                }
                 else => unreachable!("Signal streams never end"),
             }
             };
 
+            // A single deadline for the whole shutdown sequence below, so that
+            // `graceful_shutdown_limit` bounds the total time spent stopping
+            // the topology and draining supervised tasks, rather than being
+            // applied as two independent full timeouts in a row.
+            let shutdown_deadline = tokio::time::Instant::now() + graceful_shutdown_limit;
+
             match signal {
                 SignalTo::Shutdown => {
+                    state_machine.handle(state_machine::Event::Shutdown).await;
                     emit!(VectorStopped);
-                    tokio::select! {
-                    _ = topology.stop() => (), // Graceful shutdown finished
-                    _ = signals.next() => {
-                        // It is highly unlikely that this event will exit from topology.
-                        emit!(VectorQuit);
-                        // Dropping the shutdown future will immediately shut the server down
+                    if let Some(topology) = state_machine.take_topology() {
+                        tokio::select! {
+                        result = tokio::time::timeout_at(shutdown_deadline, topology.stop()) => {
+                            if result.is_err() {
+                                // The graceful shutdown deadline elapsed before the topology
+                                // finished stopping on its own; force-drop whatever is left.
+                                emit!(VectorGracefulShutdownTimeout {
+                                    limit: graceful_shutdown_limit,
+                                });
+                            }
+                        }
+                        _ = signals.next() => {
+                            // It is highly unlikely that this event will exit from topology.
+                            emit!(VectorQuit);
+                            // Dropping the shutdown future will immediately shut the server down
+                        }
                     }
-                }
+                    }
+                    let remaining = shutdown_deadline.saturating_duration_since(tokio::time::Instant::now());
+                    task_supervisor.shutdown(remaining).await;
                 }
                 SignalTo::Quit => {
+                    state_machine.handle(state_machine::Event::Quit).await;
                     // It is highly unlikely that this event will exit from topology.
                     emit!(VectorQuit);
-                    drop(topology);
+                    drop(state_machine.take_topology());
+                    let remaining = shutdown_deadline.saturating_duration_since(tokio::time::Instant::now());
+                    task_supervisor.shutdown(remaining).await;
                 }
                 SignalTo::Reload => unreachable!(),
             }
+
+            // Dropping the profiler flushes the recorded allocation profile to
+            // disk; this must only happen once the topology has fully stopped.
+            #[cfg(feature = "allocation-tracing")]
+            drop(heap_profiler);
         });
     }
 }
@@ -0,0 +1,30 @@
+//! Optional `dhat`-based heap profiling, gated behind the
+//! `allocation-tracing` feature.
+//!
+//! Passing `--profile-heap <path>` (or setting `VECTOR_PROFILE_HEAP`) swaps
+//! in a `dhat`-instrumented global allocator for the lifetime of the
+//! process. The profile is only flushed to `<path>` once the returned
+//! [`HeapProfiler`] guard is dropped, so [`Application::run`](crate::app::Application::run)
+//! must keep it alive until after `topology.stop()` has completed.
+
+#[cfg(feature = "allocation-tracing")]
+#[global_allocator]
+static ALLOCATOR: dhat::Alloc = dhat::Alloc;
+
+/// Holds the live `dhat` profiler for the duration of the process.
+#[cfg(feature = "allocation-tracing")]
+pub struct HeapProfiler {
+    _guard: dhat::Profiler,
+}
+
+#[cfg(feature = "allocation-tracing")]
+impl HeapProfiler {
+    /// Start recording allocations, writing a `dhat-heap.json`-format
+    /// profile to `path` once the returned guard is dropped.
+    pub fn start(path: std::path::PathBuf) -> Self {
+        info!(message = "Heap profiling enabled.", ?path);
+        Self {
+            _guard: dhat::Profiler::builder().file_name(path).build(),
+        }
+    }
+}
@@ -0,0 +1,255 @@
+//! A small typed state machine driving the reload/shutdown loop in
+//! [`Application::run`](crate::app::Application::run).
+//!
+//! Modeling the loop as explicit states makes the rollback behavior of a
+//! failed reload obvious: the `Running` state is only ever replaced once a
+//! new config has been built and respawned successfully. A reload that
+//! fails, or only partially applies, simply leaves the previous `Running`
+//! state (and its config) in place rather than advancing to some ambiguous
+//! in-between state.
+
+use std::{mem, sync::Arc};
+
+use tokio::sync::watch;
+
+use crate::{config::Config, topology::RunningTopology};
+
+/// Events that drive transitions of the [`StateMachine`].
+pub enum Event {
+    /// A new config was loaded from disk and should be applied.
+    UpdateConfig(Box<Config>),
+    /// The new config failed to load or parse; the current state is kept.
+    ConfigLoadFailed,
+    /// A running component crashed; the topology should shut down.
+    ComponentCrashed,
+    /// All sources finished on their own.
+    SourcesFinished,
+    /// A graceful shutdown was requested.
+    Shutdown,
+    /// An immediate shutdown was requested.
+    Quit,
+}
+
+/// The result of handling an [`Event`], telling the caller what to do next.
+pub enum Outcome {
+    /// A reload succeeded; the new config is now being served.
+    Reloaded,
+    /// The new config failed to load or parse, so no reload was attempted;
+    /// the previous config, if any, is still being served.
+    ConfigLoadFailed,
+    /// The new config loaded, but the topology failed to respawn with it;
+    /// the previous config, if any, is still being served.
+    ReloadFailed,
+    /// The machine moved to [`Status::Errored`] and cannot recover; the
+    /// caller should proceed straight to shutdown.
+    Fatal,
+    /// The caller should begin a graceful shutdown.
+    Shutdown,
+    /// The caller should quit immediately, without waiting on the topology.
+    Quit,
+}
+
+/// A cheap, `Clone`-able summary of the current state, suitable for
+/// reporting outside of the state machine (e.g. from the API server).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Status {
+    Startup,
+    Running,
+    Errored,
+    Stopped,
+}
+
+/// The state of the running `vector` process.
+enum State {
+    Startup,
+    Running {
+        config: Arc<Config>,
+        topology: RunningTopology,
+    },
+    Errored,
+    Stopped,
+}
+
+/// Drives the reload/shutdown loop and exposes its current [`Status`] via a
+/// `watch` channel so other subsystems (namely the API server) can report it
+/// without reaching into the topology directly.
+pub struct StateMachine {
+    state: State,
+    status_tx: watch::Sender<Status>,
+}
+
+impl StateMachine {
+    pub fn new() -> (Self, watch::Receiver<Status>) {
+        let (status_tx, status_rx) = watch::channel(Status::Startup);
+        (
+            Self {
+                state: State::Startup,
+                status_tx,
+            },
+            status_rx,
+        )
+    }
+
+    /// Enter the `Running` state with a freshly started topology. Called
+    /// once at the end of startup.
+    pub fn start(&mut self, topology: RunningTopology) {
+        let config = Arc::new(topology.config().clone());
+        self.state = State::Running { config, topology };
+        self.set_status(Status::Running);
+    }
+
+    /// Handle an [`Event`], respawning the topology in place for
+    /// [`Event::UpdateConfig`] and otherwise just classifying the event into
+    /// an [`Outcome`] for the caller to act on.
+    pub async fn handle(&mut self, event: Event) -> Outcome {
+        match event {
+            Event::UpdateConfig(new_config) => match &mut self.state {
+                State::Running { config, topology } => {
+                    match topology.reload_config_and_respawn(*new_config).await {
+                        Ok(true) => {
+                            *config = Arc::new(topology.config().clone());
+                            Outcome::Reloaded
+                        }
+                        // The previous `Running` config is left untouched in both
+                        // of the failure cases below, so a bad reload never
+                        // degrades the config the topology is actually serving.
+                        Ok(false) => Outcome::ReloadFailed,
+                        Err(()) => {
+                            self.set_status(Status::Errored);
+                            Outcome::Fatal
+                        }
+                    }
+                }
+                _ => Outcome::ReloadFailed,
+            },
+            Event::ConfigLoadFailed => Outcome::ConfigLoadFailed,
+            Event::ComponentCrashed => {
+                // Report the crash as `Errored`. `take_topology` replaces this
+                // with `Stopped` once the topology is taken for shutdown, so
+                // from then on a crash-triggered shutdown reports the same
+                // status as a clean one.
+                self.set_status(Status::Errored);
+                Outcome::Shutdown
+            }
+            Event::SourcesFinished | Event::Shutdown => Outcome::Shutdown,
+            Event::Quit => Outcome::Quit,
+        }
+    }
+
+    /// The topology of the current `Running` state, if any.
+    pub fn topology(&self) -> Option<&RunningTopology> {
+        match &self.state {
+            State::Running { topology, .. } => Some(topology),
+            _ => None,
+        }
+    }
+
+    /// The topology of the current `Running` state, if any, mutably.
+    pub fn topology_mut(&mut self) -> Option<&mut RunningTopology> {
+        match &mut self.state {
+            State::Running { topology, .. } => Some(topology),
+            _ => None,
+        }
+    }
+
+    /// Move out of `Running`, transitioning to [`Status::Stopped`] and
+    /// returning the topology so the caller can shut it down. Returns `None`
+    /// if the machine was not `Running`.
+    pub fn take_topology(&mut self) -> Option<RunningTopology> {
+        match mem::replace(&mut self.state, State::Stopped) {
+            State::Running { topology, .. } => {
+                self.set_status(Status::Stopped);
+                Some(topology)
+            }
+            other => {
+                self.state = other;
+                None
+            }
+        }
+    }
+
+    /// The config currently being served, if the machine is `Running`.
+    ///
+    /// This is deliberately separate from `topology().config()`: it is only
+    /// ever advanced on a *successful* reload, so it keeps reporting the
+    /// last known-good config even if a failed reload left the topology
+    /// itself in some partially-applied state.
+    pub fn config(&self) -> Option<&Config> {
+        match &self.state {
+            State::Running { config, .. } => Some(config),
+            _ => None,
+        }
+    }
+
+    fn set_status(&mut self, status: Status) {
+        let _ = self.status_tx.send(status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // These cover the event paths that don't require a live `RunningTopology`
+    // (everything except `UpdateConfig`, which is exercised end-to-end by the
+    // topology's own reload tests). `start()` is deliberately not called, so
+    // the machine's state stays `Startup` throughout; only the reported
+    // status can change.
+
+    #[tokio::test]
+    async fn new_machine_starts_in_startup() {
+        let (_state_machine, status_rx) = StateMachine::new();
+        assert_eq!(*status_rx.borrow(), Status::Startup);
+    }
+
+    #[tokio::test]
+    async fn component_crashed_reports_errored_and_shuts_down() {
+        let (mut state_machine, status_rx) = StateMachine::new();
+
+        let outcome = state_machine.handle(Event::ComponentCrashed).await;
+
+        assert!(matches!(outcome, Outcome::Shutdown));
+        assert_eq!(*status_rx.borrow(), Status::Errored);
+    }
+
+    #[tokio::test]
+    async fn sources_finished_and_shutdown_both_request_shutdown() {
+        let (mut state_machine, _status_rx) = StateMachine::new();
+
+        assert!(matches!(
+            state_machine.handle(Event::SourcesFinished).await,
+            Outcome::Shutdown
+        ));
+        assert!(matches!(
+            state_machine.handle(Event::Shutdown).await,
+            Outcome::Shutdown
+        ));
+    }
+
+    #[tokio::test]
+    async fn quit_requests_quit() {
+        let (mut state_machine, _status_rx) = StateMachine::new();
+
+        assert!(matches!(state_machine.handle(Event::Quit).await, Outcome::Quit));
+    }
+
+    #[tokio::test]
+    async fn config_load_failed_is_distinct_from_reload_failed() {
+        let (mut state_machine, _status_rx) = StateMachine::new();
+
+        assert!(matches!(
+            state_machine.handle(Event::ConfigLoadFailed).await,
+            Outcome::ConfigLoadFailed
+        ));
+    }
+
+    #[test]
+    fn topology_accessors_are_none_before_start() {
+        let (mut state_machine, _status_rx) = StateMachine::new();
+
+        assert!(state_machine.topology().is_none());
+        assert!(state_machine.topology_mut().is_none());
+        assert!(state_machine.config().is_none());
+        assert!(state_machine.take_topology().is_none());
+    }
+}
@@ -0,0 +1,189 @@
+//! A small registry for long-running auxiliary tasks (the heartbeat, the
+//! API server, the config watcher thread, ...) that would otherwise be
+//! launched with a bare `tokio::spawn` and implicitly dropped on shutdown.
+//!
+//! Each registered task is handed the receiving half of a per-task
+//! cancellation signal, which it is expected to race against its own work,
+//! and the supervisor stores a matching join handle so `shutdown` can wait
+//! for every task to actually finish (within a deadline) instead of just
+//! dropping them. A panic inside a supervised task is caught and emitted as
+//! an internal event rather than silently tearing down the task unnoticed.
+
+use std::{future::Future, panic::AssertUnwindSafe, time::Duration};
+
+use futures::{future::join_all, FutureExt};
+use tokio::{
+    sync::{mpsc, oneshot},
+    task::JoinHandle,
+};
+
+use crate::internal_events::TaskPanicked;
+
+struct SupervisedTask {
+    name: &'static str,
+    handle: JoinHandle<()>,
+    shutdown: oneshot::Sender<()>,
+}
+
+/// Owned by [`Application`](crate::app::Application) for the lifetime of
+/// the process; tasks are registered during startup and drained during
+/// shutdown.
+#[derive(Default)]
+pub struct TaskSupervisor {
+    tasks: Vec<SupervisedTask>,
+}
+
+impl TaskSupervisor {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Spawn `make_task`, handing it the receiving half of a cancellation
+    /// signal it should select against to shut down promptly. A panic
+    /// inside the task is caught and reported as a [`TaskPanicked`] event
+    /// instead of propagating into the runtime.
+    pub fn spawn<F, Fut>(&mut self, name: &'static str, make_task: F)
+    where
+        F: FnOnce(oneshot::Receiver<()>) -> Fut,
+        Fut: Future<Output = ()> + Send + 'static,
+    {
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let task = make_task(shutdown_rx);
+        let handle = tokio::spawn(async move {
+            if AssertUnwindSafe(task).catch_unwind().await.is_err() {
+                emit!(TaskPanicked { task: name });
+            }
+        });
+
+        self.tasks.push(SupervisedTask {
+            name,
+            handle,
+            shutdown: shutdown_tx,
+        });
+    }
+
+    /// Register a task that was already spawned elsewhere (e.g. the API
+    /// server, which needs to hand its join handle back to the caller for
+    /// other reasons), along with the sender half of whatever cancellation
+    /// signal it was given.
+    pub fn register(&mut self, name: &'static str, handle: JoinHandle<()>, shutdown: oneshot::Sender<()>) {
+        // Wrap the existing handle so a panic in the task is reported the
+        // same way as one caught by `spawn`.
+        let handle = tokio::spawn(async move {
+            if let Err(error) = handle.await {
+                if error.is_panic() {
+                    emit!(TaskPanicked { task: name });
+                }
+            }
+        });
+
+        self.tasks.push(SupervisedTask {
+            name,
+            handle,
+            shutdown,
+        });
+    }
+
+    /// Register a blocking `std::thread`, such as the config watcher. The
+    /// thread is expected to observe `cancel` on its own and return
+    /// promptly once signaled; its completion is adapted onto a
+    /// [`JoinHandle`] via `spawn_blocking` so it can be awaited alongside
+    /// the async tasks above.
+    pub fn register_thread(
+        &mut self,
+        name: &'static str,
+        thread: std::thread::JoinHandle<()>,
+        cancel: mpsc::Sender<()>,
+    ) {
+        self.spawn(name, move |shutdown| async move {
+            if shutdown.await.is_ok() {
+                let _ = cancel.send(()).await;
+            }
+            if let Ok(Err(_)) = tokio::task::spawn_blocking(move || thread.join()).await {
+                emit!(TaskPanicked { task: name });
+            }
+        });
+    }
+
+    /// Signal every registered task to stop and wait for them all to
+    /// finish, up to `deadline`. Tasks still outstanding once the deadline
+    /// elapses are left to be dropped by the runtime.
+    pub async fn shutdown(self, deadline: Duration) {
+        let names: Vec<_> = self.tasks.iter().map(|task| task.name).collect();
+
+        let handles = self.tasks.into_iter().map(|task| {
+            let _ = task.shutdown.send(());
+            task.handle
+        });
+
+        if tokio::time::timeout(deadline, join_all(handles))
+            .await
+            .is_err()
+        {
+            warn!(
+                message = "Not all background tasks drained before the graceful shutdown deadline.",
+                tasks = ?names,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn shutdown_signals_and_drains_spawned_tasks() {
+        let ran = Arc::new(AtomicBool::new(false));
+        let mut supervisor = TaskSupervisor::new();
+        supervisor.spawn("task", {
+            let ran = Arc::clone(&ran);
+            |shutdown| async move {
+                let _ = shutdown.await;
+                ran.store(true, Ordering::SeqCst);
+            }
+        });
+
+        supervisor.shutdown(Duration::from_secs(1)).await;
+
+        assert!(ran.load(Ordering::SeqCst));
+    }
+
+    #[tokio::test]
+    async fn shutdown_completes_despite_a_panicking_task() {
+        let mut supervisor = TaskSupervisor::new();
+        supervisor.spawn("panics", |_shutdown| async move {
+            panic!("boom");
+        });
+        supervisor.spawn("well_behaved", |shutdown| async move {
+            let _ = shutdown.await;
+        });
+
+        // Neither the panic nor a second, well-behaved task should keep
+        // `shutdown` from returning within its deadline.
+        tokio::time::timeout(Duration::from_secs(1), supervisor.shutdown(Duration::from_secs(1)))
+            .await
+            .expect("shutdown should not hang");
+    }
+
+    #[tokio::test]
+    async fn register_thread_forwards_cancellation_and_joins() {
+        let (cancel_tx, mut cancel_rx) = mpsc::channel(1);
+        let thread = std::thread::spawn(move || {
+            // Block until the supervisor forwards the cancellation signal.
+            let _ = cancel_rx.blocking_recv();
+        });
+
+        let mut supervisor = TaskSupervisor::new();
+        supervisor.register_thread("thread", thread, cancel_tx);
+
+        tokio::time::timeout(Duration::from_secs(1), supervisor.shutdown(Duration::from_secs(1)))
+            .await
+            .expect("shutdown should join the thread once cancellation is forwarded");
+    }
+}